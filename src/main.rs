@@ -11,18 +11,32 @@ extern crate regex;
 extern crate select;
 extern crate rustc_serialize;
 extern crate argparse;
+extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
 use std::io;
+use std::thread;
+use std::time::Duration;
 use prettytable::Table;
 use prettytable::row::Row;
 use prettytable::cell::Cell;
 use backtrace::Backtrace;
-use argparse::{ArgumentParser, StoreOption};
+use argparse::{ArgumentParser, Store, StoreOption, StoreTrue};
 use chrono::{DateTime, UTC, Local, TimeZone};
+use rand::Rng;
+
+use std::sync::Arc;
 
 use hyper::Client;
 use hyper::client::response::Response;
 use hyper::header::ContentType;
+use hyper::server;
+use hyper::net::Fresh;
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
 
 use regex::Regex;
 
@@ -47,6 +61,7 @@ pub enum ErrorType {
     ProcessResponseFailedError(String),
     HtmlStructureError(String),
     DateTimeParseError(chrono::ParseError),
+    UnknownCarrierError(String),
 }
 
 #[derive(Debug)]
@@ -56,23 +71,47 @@ pub struct Error {
 }
 
 
+fn _error_type_message(error_type: &ErrorType) -> String {
+    match *error_type {
+        ErrorType::HtmlStructureError(ref message) => {
+            format!("Wrong HTML document structure: {}", message)
+        }
+        ErrorType::TrackingRequestError(code, ref content, ref message) => {
+            let content = content.as_ref()
+                .map(|content| String::from_utf8_lossy(content).into_owned())
+                .unwrap_or("".to_string());
+
+            format!("{}: {}. {}",
+                    code,
+                    content,
+                    message.as_ref().unwrap_or(&"None".to_string()))
+        }
+        ErrorType::UnknownCarrierError(ref carrier) => {
+            format!("Unknown carrier: \"{}\"", carrier)
+        }
+        ErrorType::ProcessResponseFailedError(ref message) => {
+            format!("Cannot process response from the tracking service: {}", message)
+        }
+        ErrorType::JsonParserError(ref error) => format!("{}", error),
+        ErrorType::Utf8Error(ref error) => format!("{}", error),
+        ErrorType::IoError(ref error) => format!("{}", error),
+        ErrorType::HyperError(ref error) => format!("{}", error),
+        ErrorType::DateTimeParseError(ref error) => format!("{}", error),
+    }
+}
+
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self.bases.first().expect("Error without bases") {
-            ErrorType::HtmlStructureError(ref message) => {
-                write!(f, "Wrong HTML document structure: {}", message)
+        for (index, error_type) in self.bases.iter().enumerate() {
+            if index > 0 {
+                write!(f, "\ncaused by: ")?;
             }
-            ErrorType::TrackingRequestError(code, ref content, ref message) => {
-                let content = content.as_ref().unwrap();
-
-                write!(f,
-                       "{}: {}. {}",
-                       code,
-                       String::from_utf8_lossy(&content),
-                       message.as_ref().unwrap_or(&"None".to_string()))
-            }
-            _ => write!(f, "{}", self.description()),
+
+            write!(f, "{}", _error_type_message(error_type))?;
         }
+
+        Ok(())
     }
 }
 
@@ -85,6 +124,7 @@ impl std::error::Error for Error {
                 "Cannot process response from the tracking service"
             }
             ErrorType::HtmlStructureError(_) => "Unexpected HTML document structure",
+            ErrorType::UnknownCarrierError(_) => "No retriever registered for the requested carrier",
             ErrorType::JsonParserError(ref error) => error.description(),
             ErrorType::Utf8Error(ref error) => error.description(),
             ErrorType::IoError(ref error) => error.description(),
@@ -170,11 +210,14 @@ impl From<chrono::ParseError> for Error {
     }
 }
 
-pub trait TrackingRetriever {
+pub trait TrackingRetriever: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn code_pattern(&self) -> Regex;
     fn get_tracking_info(&self, tracking_code: &str) -> Result<Vec<TrackingStatusInfo>>;
 }
 
-#[derive(Default, Debug, Eq, PartialEq)]
+// Requires chrono's "serde" feature so `DateTime<UTC>` serializes as RFC3339.
+#[derive(Default, Debug, Eq, PartialEq, Serialize)]
 pub struct TrackingStatusInfo {
     date: Option<DateTime<UTC>>,
     zip_code: Option<String>,
@@ -183,7 +226,59 @@ pub struct TrackingStatusInfo {
     weight: Option<String>,
 }
 
-pub struct EMSRussianPostRetriever;
+/// Retry policy for transient network failures: attempt `n` (starting at 0) sleeps for
+/// `min(base * 2^n, cap)` plus jitter in `[0, base)` before the next try.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    base_ms: u64,
+    cap_ms: u64,
+    max_retries: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            base_ms: 200,
+            cap_ms: 5000,
+            max_retries: 3,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        let backoff = self.base_ms.saturating_mul(1u64 << attempt.min(63));
+        let backoff = std::cmp::min(backoff, self.cap_ms);
+        let jitter = rand::thread_rng().gen_range(0, std::cmp::max(self.base_ms, 1));
+
+        Duration::from_millis(backoff + jitter)
+    }
+}
+
+fn _is_retryable(error: &Error) -> bool {
+    error.bases.iter().any(|base| match *base {
+        ErrorType::IoError(_) | ErrorType::HyperError(_) => true,
+        ErrorType::TrackingRequestError(status, _, _) => status.is_server_error(),
+        _ => false,
+    })
+}
+
+pub struct EMSRussianPostRetriever {
+    retry_policy: RetryPolicy,
+}
+
+impl Default for EMSRussianPostRetriever {
+    fn default() -> EMSRussianPostRetriever {
+        EMSRussianPostRetriever { retry_policy: RetryPolicy::default() }
+    }
+}
+
+impl EMSRussianPostRetriever {
+    pub fn new(retry_policy: RetryPolicy) -> EMSRussianPostRetriever {
+        EMSRussianPostRetriever { retry_policy: retry_policy }
+    }
+}
+
 static EMS_RUSSIAN_POST_URL: &'static str = "http://www.emspost.ru/ru/tracking.aspx/getEmsInfo";
 
 
@@ -193,6 +288,72 @@ mod test {
     use super::*;
     use chrono::{UTC, TimeZone};
 
+    struct MockRetriever {
+        name: &'static str,
+        pattern: &'static str,
+        should_succeed: bool,
+    }
+
+    impl TrackingRetriever for MockRetriever {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn code_pattern(&self) -> Regex {
+            Regex::new(self.pattern).unwrap()
+        }
+
+        fn get_tracking_info(&self, _tracking_code: &str) -> Result<Vec<TrackingStatusInfo>> {
+            if self.should_succeed {
+                Ok(vec![TrackingStatusInfo {
+                            zip_code: Some(self.name.to_string()),
+                            ..TrackingStatusInfo::default()
+                        }])
+            } else {
+                Err(Error::new(ErrorType::ProcessResponseFailedError("mock failure".to_string())))
+            }
+        }
+    }
+
+    #[test]
+    fn test_registry_dispatch_should_use_the_single_matching_retriever() {
+        let registry = Registry {
+            retrievers: vec![
+                Box::new(MockRetriever { name: "a", pattern: "^A", should_succeed: true }),
+                Box::new(MockRetriever { name: "b", pattern: "^B", should_succeed: true }),
+            ],
+        };
+
+        let result = registry.dispatch("A123", None).unwrap();
+        assert_eq!(result[0].zip_code, Some("a".to_string()));
+    }
+
+    #[test]
+    fn test_registry_dispatch_should_honor_carrier_override() {
+        let registry = Registry {
+            retrievers: vec![
+                Box::new(MockRetriever { name: "a", pattern: "^A", should_succeed: true }),
+                Box::new(MockRetriever { name: "b", pattern: "^A", should_succeed: true }),
+            ],
+        };
+
+        let result = registry.dispatch("A123", Some("b")).unwrap();
+        assert_eq!(result[0].zip_code, Some("b".to_string()));
+    }
+
+    #[test]
+    fn test_registry_dispatch_should_fall_back_to_trying_all_retrievers_when_none_match() {
+        let registry = Registry {
+            retrievers: vec![
+                Box::new(MockRetriever { name: "a", pattern: "^Z", should_succeed: false }),
+                Box::new(MockRetriever { name: "b", pattern: "^Z", should_succeed: true }),
+            ],
+        };
+
+        let result = registry.dispatch("unmatched", None).unwrap();
+        assert_eq!(result[0].zip_code, Some("b".to_string()));
+    }
+
     static CORRECT_DOCUMENT: &'static str = r#"
 <table class='emsHeader'>
   <tr>
@@ -268,14 +429,14 @@ mod test {
 
     #[test]
     fn test_ems_russian_post_retriever_parse_date_should_parse_correct_date() {
-        let retriever = EMSRussianPostRetriever;
+        let retriever = EMSRussianPostRetriever::default();
 
         assert_eq!(retriever._parse_date("24.08.2016 11:35").unwrap(), UTC.ymd(2016, 08, 24).and_hms(11, 35, 0));
     }
 
     #[test]
     fn test_ems_russian_post_retriever_parse_table_should_parse_correct_document() {
-        let retriever = EMSRussianPostRetriever;
+        let retriever = EMSRussianPostRetriever::default();
 
         let result =
             vec![TrackingStatusInfo {
@@ -305,6 +466,95 @@ mod test {
 
         assert_eq!(retriever._parse_table(CORRECT_DOCUMENT).unwrap(), result);
     }
+
+    fn _duration_as_millis(duration: Duration) -> u64 {
+        duration.as_secs() * 1000 + (duration.subsec_nanos() as u64) / 1_000_000
+    }
+
+    #[test]
+    fn test_retry_policy_delay_for_attempt_should_respect_base_and_cap() {
+        let policy = RetryPolicy {
+            base_ms: 100,
+            cap_ms: 1000,
+            max_retries: 5,
+        };
+
+        let first_attempt = _duration_as_millis(policy.delay_for_attempt(0));
+        assert!(first_attempt >= 100 && first_attempt < 200);
+
+        let late_attempt = _duration_as_millis(policy.delay_for_attempt(10));
+        assert!(late_attempt >= 1000 && late_attempt < 1100);
+    }
+
+    #[test]
+    fn test_is_retryable_should_flag_io_and_server_errors_only() {
+        let io_error = Error::new(ErrorType::IoError(std::io::Error::new(std::io::ErrorKind::Other, "boom")));
+        assert!(_is_retryable(&io_error));
+
+        let server_error = Error::new(ErrorType::TrackingRequestError(hyper::status::StatusCode::InternalServerError, None, None));
+        assert!(_is_retryable(&server_error));
+
+        let client_error = Error::new(ErrorType::TrackingRequestError(hyper::status::StatusCode::NotFound, None, None));
+        assert!(!_is_retryable(&client_error));
+
+        let html_error = Error::new(ErrorType::HtmlStructureError("bad".to_string()));
+        assert!(!_is_retryable(&html_error));
+    }
+
+    #[test]
+    fn test_delivery_state_classify_should_recognize_known_phrases() {
+        assert_eq!(DeliveryState::classify("Прием, Единичный"), DeliveryState::InTransit);
+        assert_eq!(DeliveryState::classify("Сортировка"), DeliveryState::InTransit);
+        assert_eq!(DeliveryState::classify("Вручение"), DeliveryState::Delivered);
+        assert_eq!(DeliveryState::classify("Неудачная попытка вручения"), DeliveryState::Exception);
+    }
+
+    #[test]
+    fn test_html_escape_should_escape_special_characters() {
+        let escaped = _html_escape(r#"<script>alert("x")</script> & co"#);
+
+        assert_eq!(escaped, "&lt;script&gt;alert(&quot;x&quot;)&lt;/script&gt; &amp; co");
+        assert!(!escaped.contains('<'));
+        assert!(!escaped.contains('>'));
+        assert!(!escaped.contains('"'));
+    }
+
+    #[test]
+    fn test_parse_track_request_should_extract_the_code() {
+        let uri = RequestUri::AbsolutePath("/track/ABC123".to_string());
+
+        assert_eq!(_parse_track_request(&uri), Some(("ABC123".to_string(), None)));
+    }
+
+    #[test]
+    fn test_parse_track_request_should_extract_the_carrier_query_parameter() {
+        let uri = RequestUri::AbsolutePath("/track/ABC123?carrier=cdek".to_string());
+
+        assert_eq!(_parse_track_request(&uri),
+                   Some(("ABC123".to_string(), Some("cdek".to_string()))));
+    }
+
+    #[test]
+    fn test_parse_track_request_should_reject_an_empty_code() {
+        let uri = RequestUri::AbsolutePath("/track/".to_string());
+
+        assert_eq!(_parse_track_request(&uri), None);
+    }
+
+    #[test]
+    fn test_parse_track_request_should_reject_a_non_track_path() {
+        let uri = RequestUri::AbsolutePath("/health".to_string());
+
+        assert_eq!(_parse_track_request(&uri), None);
+    }
+
+    #[test]
+    fn test_csv_field_should_quote_and_escape_special_characters() {
+        assert_eq!(_csv_field("Санкт-Петербург"), "Санкт-Петербург");
+        assert_eq!(_csv_field("Прием, Единичный"), "\"Прием, Единичный\"");
+        assert_eq!(_csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(_csv_field("line one\nline two"), "\"line one\nline two\"");
+    }
 }
 
 
@@ -388,7 +638,7 @@ impl EMSRussianPostRetriever {
         return self._parse_table(table_str);
     }
 
-    fn _make_request(&self, tracking_code: &str) -> Result<Vec<u8>> {
+    fn _make_request_once(&self, tracking_code: &str) -> Result<Vec<u8>> {
         let client = hyper::Client::new();
         let mut response = client.post(EMS_RUSSIAN_POST_URL)
             .body(&format!("{{\"emsNumber\": \"{}\"}}",
@@ -408,10 +658,54 @@ impl EMSRussianPostRetriever {
         }
     }
 
+    fn _make_request(&self, tracking_code: &str) -> Result<Vec<u8>> {
+        let mut attempts = Vec::<Error>::new();
+        let max_attempts = self.retry_policy.max_retries.saturating_add(1);
+
+        for attempt in 0..max_attempts {
+            match self._make_request_once(tracking_code) {
+                Ok(content) => return Ok(content),
+                Err(error) => {
+                    let retryable = _is_retryable(&error);
+                    attempts.push(error);
+
+                    if !retryable || attempt == self.retry_policy.max_retries {
+                        break;
+                    }
+
+                    thread::sleep(self.retry_policy.delay_for_attempt(attempt));
+                }
+            }
+        }
+
+        let mut bases = Vec::<ErrorType>::new();
+        for error in attempts.into_iter().rev() {
+            bases.extend(error.bases);
+        }
+
+        if bases.is_empty() {
+            bases.push(ErrorType::ProcessResponseFailedError("No request attempts were made"
+                .to_string()));
+        }
+
+        Err(Error {
+            bases: bases,
+            backtrace: Backtrace::new(),
+        })
+    }
+
 }
 
 impl TrackingRetriever for EMSRussianPostRetriever {
 
+    fn name(&self) -> &'static str {
+        "ems-russian-post"
+    }
+
+    fn code_pattern(&self) -> Regex {
+        Regex::new(r"^[A-Z]{2}\d{9}RU$").unwrap()
+    }
+
     fn get_tracking_info(&self, tracking_code: &str) -> Result<Vec<TrackingStatusInfo>> {
         let response = self._make_request(tracking_code)?;
 
@@ -420,16 +714,169 @@ impl TrackingRetriever for EMSRussianPostRetriever {
 }
 
 
+/// A carrier we know the tracking-code shape of but don't yet scrape.
+/// Demonstrates that the `TrackingRetriever` abstraction isn't tied to EMS.
+pub struct CdekRetriever;
+
+
+impl TrackingRetriever for CdekRetriever {
+
+    fn name(&self) -> &'static str {
+        "cdek"
+    }
+
+    fn code_pattern(&self) -> Regex {
+        Regex::new(r"^\d{10,14}$").unwrap()
+    }
+
+    fn get_tracking_info(&self, _tracking_code: &str) -> Result<Vec<TrackingStatusInfo>> {
+        Err(Error::new(ErrorType::ProcessResponseFailedError("CDEK tracking is not implemented yet"
+            .to_string())))
+    }
+}
+
+
+pub struct Registry {
+    retrievers: Vec<Box<TrackingRetriever>>,
+}
+
+
+impl Registry {
+    pub fn new(retry_policy: RetryPolicy) -> Registry {
+        Registry {
+            retrievers: vec![
+                Box::new(EMSRussianPostRetriever::new(retry_policy)),
+                Box::new(CdekRetriever),
+            ],
+        }
+    }
+
+    pub fn dispatch(&self, tracking_code: &str, carrier: Option<&str>) -> Result<Vec<TrackingStatusInfo>> {
+        if let Some(carrier_name) = carrier {
+            let retriever = self.retrievers
+                .iter()
+                .find(|retriever| retriever.name() == carrier_name)
+                .ok_or(Error::new(ErrorType::UnknownCarrierError(carrier_name.to_string())))?;
+
+            return retriever.get_tracking_info(tracking_code);
+        }
+
+        let matching: Vec<&Box<TrackingRetriever>> = self.retrievers
+            .iter()
+            .filter(|retriever| retriever.code_pattern().is_match(tracking_code))
+            .collect();
+
+        if matching.len() == 1 {
+            return matching[0].get_tracking_info(tracking_code);
+        }
+
+        let mut last_error = None;
+
+        for retriever in &self.retrievers {
+            match retriever.get_tracking_info(tracking_code) {
+                Ok(tracking_info) => return Ok(tracking_info),
+                Err(error) => last_error = Some(error),
+            }
+        }
+
+        Err(last_error.expect("Registry has no retrievers"))
+    }
+}
+
+
+#[derive(Debug, Clone, Copy)]
+enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+    Html,
+}
+
+impl Default for OutputFormat {
+    fn default() -> OutputFormat {
+        OutputFormat::Table
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<OutputFormat, String> {
+        match value.to_lowercase().as_str() {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            "html" => Ok(OutputFormat::Html),
+            _ => Err(format!("Unknown output format: \"{}\"", value)),
+        }
+    }
+}
+
+
+/// Coarse classification of a tracking event's free-text `status`, analogous to an
+/// ok/warn/error indicator. Independently useful beyond display, e.g. for the HTTP API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DeliveryState {
+    InTransit,
+    Exception,
+    Delivered,
+}
+
+impl DeliveryState {
+    pub fn classify(status: &str) -> DeliveryState {
+        if status.contains("Неудачная попытка вручения") {
+            DeliveryState::Exception
+        } else if status.contains("Вручение") {
+            DeliveryState::Delivered
+        } else {
+            DeliveryState::InTransit
+        }
+    }
+
+    fn indicator_color(&self) -> &'static str {
+        match *self {
+            DeliveryState::InTransit => "#2f80ed",
+            DeliveryState::Exception => "#eb5757",
+            DeliveryState::Delivered => "#27ae60",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match *self {
+            DeliveryState::InTransit => "In transit",
+            DeliveryState::Exception => "Exception",
+            DeliveryState::Delivered => "Delivered",
+        }
+    }
+}
+
+
 struct Settings {
     tracking_code: Option<String>,
+    carrier: Option<String>,
+    retry_base_ms: u64,
+    retry_cap_ms: u64,
+    max_retries: u32,
+    format: OutputFormat,
+    serve: Option<String>,
+    debug: bool,
 }
 
 
 
 fn _parse_arguments() -> Settings {
 
+    let defaults = RetryPolicy::default();
+
     let mut settings = Settings {
-        tracking_code: None
+        tracking_code: None,
+        carrier: None,
+        retry_base_ms: defaults.base_ms,
+        retry_cap_ms: defaults.cap_ms,
+        max_retries: defaults.max_retries,
+        format: OutputFormat::default(),
+        serve: None,
+        debug: false,
     };
 
     {
@@ -437,6 +884,20 @@ fn _parse_arguments() -> Settings {
         parser.set_description("download delivery status information by the tracking code");
         parser.refer(&mut settings.tracking_code)
             .add_option(&["-C", "--tracking-code"], StoreOption, "get tracking information for the given code");
+        parser.refer(&mut settings.carrier)
+            .add_option(&["--carrier"], StoreOption, "force a specific carrier retriever instead of auto-detecting it from the tracking code");
+        parser.refer(&mut settings.retry_base_ms)
+            .add_option(&["--retry-base-ms"], Store, "base delay, in milliseconds, before the first retry of a failed request");
+        parser.refer(&mut settings.retry_cap_ms)
+            .add_option(&["--retry-cap-ms"], Store, "maximum backoff delay, in milliseconds, between retries");
+        parser.refer(&mut settings.max_retries)
+            .add_option(&["--max-retries"], Store, "maximum number of retries for a failed request");
+        parser.refer(&mut settings.format)
+            .add_option(&["--format"], Store, "output format: table, json, csv or html");
+        parser.refer(&mut settings.serve)
+            .add_option(&["--serve"], StoreOption, "start an HTTP server on the given address instead of a one-shot lookup");
+        parser.refer(&mut settings.debug)
+            .add_option(&["--debug"], StoreTrue, "print the full error cause chain and backtrace on failure");
 
         parser.parse_args_or_exit();
     }
@@ -445,12 +906,25 @@ fn _parse_arguments() -> Settings {
 }
 
 
-fn display_error(error: &Error) {
+fn display_error(error: &Error, debug: bool) {
     println!("Cannot get tracking information");
     println!("{}", error);
+
+    if debug {
+        println!("{:?}", error.backtrace);
+    }
+}
+
+fn display_tracking_info(tracking_info: Vec<TrackingStatusInfo>, format: OutputFormat) {
+    match format {
+        OutputFormat::Table => _display_table(tracking_info),
+        OutputFormat::Json => _display_json(&tracking_info),
+        OutputFormat::Csv => _display_csv(&tracking_info),
+        OutputFormat::Html => _display_html(&tracking_info),
+    }
 }
 
-fn display_tracking_info(tracking_info: Vec<TrackingStatusInfo>) {
+fn _display_table(tracking_info: Vec<TrackingStatusInfo>) {
     let mut table = Table::new();
 
     table.set_titles(row!["Date", "ZIP code", "Description", "Status", "Weight"]);
@@ -474,14 +948,184 @@ fn display_tracking_info(tracking_info: Vec<TrackingStatusInfo>) {
     print!("{}", table);
 }
 
+fn _display_json(tracking_info: &Vec<TrackingStatusInfo>) {
+    match serde_json::to_string_pretty(tracking_info) {
+        Ok(json) => println!("{}", json),
+        Err(error) => println!("Cannot serialize tracking information to JSON: {}", error),
+    }
+}
+
+fn _csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace("\"", "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn _display_csv(tracking_info: &Vec<TrackingStatusInfo>) {
+    println!("date,zip_code,description,status,weight");
+
+    for line in tracking_info {
+        let date_string: String = match line.date {
+            Some(date) => format!("{}", date),
+            None => "-".to_string(),
+        };
+
+        println!("{},{},{},{},{}",
+                 _csv_field(&date_string),
+                 _csv_field(&line.zip_code.clone().unwrap_or("-".to_string())),
+                 _csv_field(&line.description.clone().unwrap_or("-".to_string())),
+                 _csv_field(&line.status.clone().unwrap_or("-".to_string())),
+                 _csv_field(&line.weight.clone().unwrap_or("-".to_string())));
+    }
+}
+
+fn _html_escape(value: &str) -> String {
+    value.replace("&", "&amp;")
+        .replace("<", "&lt;")
+        .replace(">", "&gt;")
+        .replace("\"", "&quot;")
+}
+
+fn _display_html(tracking_info: &Vec<TrackingStatusInfo>) {
+    println!("<!DOCTYPE html>");
+    println!("<html>");
+    println!("<head><meta charset=\"utf-8\"><title>Tracking status</title></head>");
+    println!("<body>");
+
+    for line in tracking_info.iter().rev() {
+        let state = line.status
+            .as_ref()
+            .map(|status| DeliveryState::classify(status))
+            .unwrap_or(DeliveryState::InTransit);
+
+        let date_string = match line.date {
+            Some(date) => format!("{}", date.with_timezone(&Local)),
+            None => "-".to_string(),
+        };
+
+        println!("<section style=\"border-left: 4px solid {}; padding-left: 8px; margin-bottom: 12px;\">",
+                 state.indicator_color());
+        println!("<strong>{}</strong> &mdash; {}", state.label(), _html_escape(&date_string));
+        println!("<p>{}</p>",
+                 _html_escape(&line.description.clone().unwrap_or("-".to_string())));
+        println!("<p>{}</p>", _html_escape(&line.status.clone().unwrap_or("-".to_string())));
+        println!("</section>");
+    }
+
+    println!("</body>");
+    println!("</html>");
+}
+
+
+#[derive(Serialize)]
+struct ApiError {
+    error: String,
+}
+
+fn _error_status(error: &Error) -> StatusCode {
+    match *error.bases.first().expect("Error without bases") {
+        ErrorType::HtmlStructureError(_) |
+        ErrorType::ProcessResponseFailedError(_) => StatusCode::BadGateway,
+        ErrorType::TrackingRequestError(code, _, _) => code,
+        ErrorType::IoError(_) | ErrorType::HyperError(_) => StatusCode::ServiceUnavailable,
+        ErrorType::UnknownCarrierError(_) => StatusCode::NotFound,
+        _ => StatusCode::InternalServerError,
+    }
+}
+
+/// Parses a `/track/{code}[?carrier=...]` request URI. Returns `None` for anything else.
+fn _parse_track_request(uri: &RequestUri) -> Option<(String, Option<String>)> {
+    let uri_string = format!("{}", uri);
+    let mut parts = uri_string.splitn(2, '?');
+    let path = parts.next().unwrap_or("");
+    let query = parts.next();
+
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    if segments.len() != 2 || segments[0] != "track" || segments[1].is_empty() {
+        return None;
+    }
+
+    let carrier = query.and_then(|query| {
+        query.split('&')
+            .filter_map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some("carrier"), Some(value)) => Some(value.to_string()),
+                    _ => None,
+                }
+            })
+            .next()
+    });
+
+    Some((segments[1].to_string(), carrier))
+}
+
+fn _respond(mut response: server::Response<Fresh>, status: StatusCode, body: &[u8]) {
+    *response.status_mut() = status;
+    response.headers_mut().set(ContentType::json());
+    response.send(body).unwrap_or_else(|error| warn!("Cannot send HTTP response: {}", error));
+}
+
+fn _handle_tracking_request(registry: &Registry, request: server::Request, response: server::Response<Fresh>) {
+    match _parse_track_request(&request.uri) {
+        Some((code, carrier)) => {
+            match registry.dispatch(&code, carrier.as_ref().map(|carrier| carrier.as_str())) {
+                Ok(tracking_info) => {
+                    let body = serde_json::to_string(&tracking_info)
+                        .unwrap_or("[]".to_string());
+                    _respond(response, StatusCode::Ok, body.as_bytes());
+                }
+                Err(error) => {
+                    let status = _error_status(&error);
+                    let body = serde_json::to_string(&ApiError { error: format!("{}", error) })
+                        .unwrap_or("{}".to_string());
+                    _respond(response, status, body.as_bytes());
+                }
+            }
+        }
+        None => {
+            let body = serde_json::to_string(&ApiError { error: "Not found".to_string() })
+                .unwrap_or("{}".to_string());
+            _respond(response, StatusCode::NotFound, body.as_bytes());
+        }
+    }
+}
+
+fn _run_server(addr: &str, retry_policy: RetryPolicy) {
+    let registry = Arc::new(Registry::new(retry_policy));
+
+    let server = server::Server::http(addr).expect("Cannot bind HTTP server");
+    server.handle(move |request: server::Request, response: server::Response<Fresh>| {
+            _handle_tracking_request(&registry, request, response);
+        })
+        .expect("Cannot start HTTP server");
+}
+
 
 pub fn main() {
     let settings = _parse_arguments();
+    let retry_policy = RetryPolicy {
+        base_ms: settings.retry_base_ms,
+        cap_ms: settings.retry_cap_ms,
+        max_retries: settings.max_retries,
+    };
+
+    if let Some(addr) = settings.serve {
+        return _run_server(&addr, retry_policy);
+    }
+
+    let registry = Registry::new(retry_policy);
+    let format = settings.format;
+    let debug = settings.debug;
 
     match settings.tracking_code {
         Some(code) => {
-            EMSRussianPostRetriever.get_tracking_info(&code)
-                .map(display_tracking_info);
+            registry.dispatch(&code, settings.carrier.as_ref().map(|carrier| carrier.as_str()))
+                .map(|tracking_info| display_tracking_info(tracking_info, format))
+                .unwrap_or_else(|error| display_error(&error, debug));
         },
         _ => {}
     }